@@ -14,7 +14,7 @@ use rocket_seek_stream::SeekStream;
 
 // stream from an in memory buffer
 #[get("/memory")]
-fn hello<'a>() -> SeekStream<'a> {
+fn hello() -> SeekStream {
     let bytes = &include_bytes!("./cruel_angels_thesis.webm")[..];
     let len = bytes.len();
     let stream = std::io::Cursor::new(bytes);
@@ -24,20 +24,20 @@ fn hello<'a>() -> SeekStream<'a> {
 
 // stream from a given filepath
 #[get("/from_path")]
-fn from_path<'a>() -> std::io::Result<SeekStream<'a>> {
-    SeekStream::from_path("fly_me_to_the_moon.webm")
+async fn from_path() -> std::io::Result<SeekStream> {
+    SeekStream::from_path("fly_me_to_the_moon.webm").await
 }
 
 // some long media
 #[get("/long")]
-fn long<'a>() -> std::io::Result<SeekStream<'a>> {
-    SeekStream::from_path("tari_tari.webm")
+async fn long() -> std::io::Result<SeekStream> {
+    SeekStream::from_path("tari_tari.webm").await
 }
 
 // some longer media
 #[get("/")]
-fn longer<'a>() -> std::io::Result<SeekStream<'a>> {
-    SeekStream::from_path("ison.webm")
+async fn longer() -> std::io::Result<SeekStream> {
+    SeekStream::from_path("ison.webm").await
 }
 
 #[launch]
@@ -0,0 +1,472 @@
+use bytes::Bytes;
+use reqwest::{header, Client};
+use rocket::futures::Stream;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// How many already-fetched intervals we'll hang onto so that a seek back into a window
+/// we've just streamed doesn't have to re-hit the network.
+const MAX_CACHED_INTERVALS: usize = 8;
+
+type RangeFuture = Pin<Box<dyn Future<Output = reqwest::Result<reqwest::Response>> + Send>>;
+type ByteStream = Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>> + Send>>;
+
+/// The state of the single in-flight range request, if any.
+enum Fetch {
+    /// Waiting on the response headers for the `GET` that was issued at `start`.
+    Requesting(RangeFuture),
+    /// Streaming the body of that response; `leftover` holds bytes of the last chunk that
+    /// didn't fit in the caller's buffer.
+    Streaming { body: ByteStream, leftover: Bytes },
+}
+
+/// An [`AsyncRead`](rocket::tokio::io::AsyncRead) + [`AsyncSeek`](rocket::tokio::io::AsyncSeek)
+/// source that lazily fetches bytes from a remote URL using HTTP range requests, so a
+/// [`crate::SeekStream`] can re-serve/seek a remote object (S3, a CDN, another range server)
+/// without downloading it whole.
+///
+/// Sequential reads are coalesced onto a single in-flight response, and a request is only
+/// re-issued when the caller seeks away from the offset that response is currently serving.
+/// A small cache of already-fetched intervals lets a seek back into a recently-streamed
+/// window avoid hitting the network again.
+pub struct HttpRangeReader {
+    client: Client,
+    url: String,
+    length: u64,
+    accept_ranges: bool,
+    offset: u64,
+    fetch: Option<(u64, Fetch)>,
+    cache: Vec<(u64, Bytes)>,
+}
+
+impl HttpRangeReader {
+    /// Probe `url` with a `HEAD` (falling back to a `Range: bytes=0-0` `GET` if the origin
+    /// doesn't support `HEAD`) to learn its length and whether it advertises
+    /// `Accept-Ranges: bytes`.
+    pub async fn new(url: impl Into<String>) -> io::Result<Self> {
+        let url = url.into();
+        let client = Client::new();
+
+        let (length, accept_ranges) = probe(&client, &url).await?;
+
+        Ok(HttpRangeReader {
+            client,
+            url,
+            length,
+            accept_ranges,
+            offset: 0,
+            fetch: None,
+            cache: Vec::new(),
+        })
+    }
+
+    /// Whether the origin advertised support for byte-range requests.
+    pub fn accepts_ranges(&self) -> bool {
+        self.accept_ranges
+    }
+
+    fn take_from_cache(&mut self, buf: &mut rocket::tokio::io::ReadBuf<'_>) -> bool {
+        let hit = self
+            .cache
+            .iter()
+            .find(|(start, chunk)| *start <= self.offset && self.offset < *start + chunk.len() as u64)
+            .map(|(start, chunk)| (*start, chunk.clone()));
+
+        let (start, chunk) = match hit {
+            Some(x) => x,
+            None => return false,
+        };
+
+        let local = (self.offset - start) as usize;
+        let available = &chunk[local..];
+        let n = std::cmp::min(available.len(), buf.remaining());
+        buf.put_slice(&available[..n]);
+        self.offset += n as u64;
+        true
+    }
+
+    fn cache_chunk(&mut self, start: u64, chunk: Bytes) {
+        if self.cache.len() >= MAX_CACHED_INTERVALS {
+            self.cache.remove(0);
+        }
+        self.cache.push((start, chunk));
+    }
+}
+
+async fn probe(client: &Client, url: &str) -> io::Result<(u64, bool)> {
+    let head = client.head(url).send().await;
+
+    if let Ok(resp) = head {
+        if resp.status().is_success() {
+            let accept_ranges = resp
+                .headers()
+                .get(header::ACCEPT_RANGES)
+                .and_then(|v| v.to_str().ok())
+                .map_or(false, |v| v == "bytes");
+            // A HEAD response has no body, so `content_length()` (the body's size) is always
+            // 0 here; the resource's size is only in the `Content-Length` header itself.
+            let length = resp
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok());
+            if let Some(len) = length {
+                return Ok((len, accept_ranges));
+            }
+        }
+    }
+
+    // The origin didn't support HEAD, or didn't report a length; fall back to asking for
+    // the first byte and reading the length out of `Content-Range`.
+    let resp = client
+        .get(url)
+        .header(header::RANGE, "bytes=0-0")
+        .send()
+        .await
+        .map_err(reqwest_io_error)?;
+
+    let accept_ranges = resp.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+    let length = if accept_ranges {
+        // A 206 to our single-byte probe has a `Content-Length` of `1`, not the resource's
+        // total size; that total is only available in `Content-Range`, so trust nothing else.
+        resp.headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit('/').next())
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    "origin answered 206 to the probing range request without a parsable Content-Range total",
+                )
+            })?
+    } else {
+        // The origin ignored the range and sent the whole body, so its `Content-Length` is
+        // genuinely the total size.
+        resp.content_length().unwrap_or(0)
+    };
+
+    Ok((length, accept_ranges))
+}
+
+fn reqwest_io_error(e: reqwest::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+fn checked_offset(base: u64, delta: i64) -> io::Result<u64> {
+    let result = if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.unsigned_abs())
+    };
+    result.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
+impl rocket::tokio::io::AsyncRead for HttpRangeReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut rocket::tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.offset >= this.length {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            // Keep serving whatever is already in flight for the current offset before
+            // reaching for the cache or starting a new request; this is what lets a run of
+            // sequential reads ride a single GET instead of re-hitting the network at every
+            // chunk boundary.
+            let fetch_matches_offset = matches!(&this.fetch, Some((start, _)) if *start == this.offset);
+
+            if !fetch_matches_offset {
+                if this.take_from_cache(buf) {
+                    return Poll::Ready(Ok(()));
+                }
+
+                let offset = this.offset;
+                if offset > 0 && !this.accept_ranges {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "origin does not advertise support for byte-range requests",
+                    )));
+                }
+
+                let req = this
+                    .client
+                    .get(&this.url)
+                    .header(header::RANGE, format!("bytes={}-", offset))
+                    .send();
+                this.fetch = Some((offset, Fetch::Requesting(Box::pin(req))));
+            }
+
+            match &mut this.fetch {
+                Some((start, Fetch::Requesting(f))) => match f.as_mut().poll(cx) {
+                    Poll::Ready(Ok(resp)) => {
+                        let requested_offset = *start;
+                        if !resp.status().is_success() {
+                            let status = resp.status();
+                            this.fetch = None;
+                            return Poll::Ready(Err(io::Error::new(
+                                io::ErrorKind::Other,
+                                format!("upstream responded with {}", status),
+                            )));
+                        }
+                        // A ranged read must come back as `206 Partial Content`; an origin
+                        // that silently ignores `Range` and answers `200` would otherwise
+                        // hand back bytes starting at 0, which we'd misinterpret as starting
+                        // at `requested_offset`.
+                        if requested_offset > 0 && resp.status() != reqwest::StatusCode::PARTIAL_CONTENT
+                        {
+                            let status = resp.status();
+                            this.fetch = None;
+                            return Poll::Ready(Err(io::Error::new(
+                                io::ErrorKind::Other,
+                                format!(
+                                    "expected 206 Partial Content for a ranged read at offset {}, got {}",
+                                    requested_offset, status
+                                ),
+                            )));
+                        }
+                        let body = Box::pin(resp.bytes_stream());
+                        this.fetch = Some((
+                            requested_offset,
+                            Fetch::Streaming {
+                                body,
+                                leftover: Bytes::new(),
+                            },
+                        ));
+                    }
+                    Poll::Ready(Err(e)) => {
+                        this.fetch = None;
+                        return Poll::Ready(Err(reqwest_io_error(e)));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                Some((start, Fetch::Streaming { body, leftover })) => {
+                    if !leftover.is_empty() {
+                        let n = std::cmp::min(leftover.len(), buf.remaining());
+                        buf.put_slice(&leftover.split_to(n));
+                        this.offset += n as u64;
+                        *start = this.offset;
+                        return Poll::Ready(Ok(()));
+                    }
+
+                    match body.as_mut().poll_next(cx) {
+                        Poll::Ready(Some(Ok(chunk))) => {
+                            this.cache_chunk(this.offset, chunk.clone());
+                            let n = std::cmp::min(chunk.len(), buf.remaining());
+                            buf.put_slice(&chunk[..n]);
+                            this.offset += n as u64;
+                            if let Some((start, Fetch::Streaming { leftover, .. })) =
+                                this.fetch.as_mut()
+                            {
+                                *leftover = chunk.slice(n..);
+                                *start = this.offset;
+                            }
+                            return Poll::Ready(Ok(()));
+                        }
+                        Poll::Ready(Some(Err(e))) => {
+                            this.fetch = None;
+                            return Poll::Ready(Err(reqwest_io_error(e)));
+                        }
+                        Poll::Ready(None) => {
+                            this.fetch = None;
+                            return Poll::Ready(Ok(()));
+                        }
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                None => unreachable!("a fetch was just installed above"),
+            }
+        }
+    }
+}
+
+impl rocket::tokio::io::AsyncSeek for HttpRangeReader {
+    fn start_seek(self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        this.offset = match position {
+            io::SeekFrom::Start(n) => n,
+            io::SeekFrom::End(n) => checked_offset(this.length, n)?,
+            io::SeekFrom::Current(n) => checked_offset(this.offset, n)?,
+        };
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+    use rocket::tokio::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Spins up a one-purpose HTTP/1.1 server on a random local port: every accepted
+    /// connection is handed its method and `Range` header, and `respond` decides the status
+    /// line, headers and body to write back. Returns the server's base URL and a counter of
+    /// how many connections it has accepted, so a test can assert on request coalescing.
+    async fn spawn_mock<F>(respond: F) -> (String, Arc<AtomicUsize>)
+    where
+        F: Fn(&str, Option<&str>) -> (&'static str, Vec<(&'static str, String)>, Vec<u8>)
+            + Send
+            + Sync
+            + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+
+        rocket::tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = match listener.accept().await {
+                    Ok(x) => x,
+                    Err(_) => return,
+                };
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 1024];
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match socket.read(&mut chunk).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                    }
+                }
+
+                let request = String::from_utf8_lossy(&buf);
+                let mut lines = request.lines();
+                let method = lines
+                    .next()
+                    .and_then(|l| l.split_whitespace().next())
+                    .unwrap_or("")
+                    .to_string();
+                let range = lines
+                    .find(|l| l.to_ascii_lowercase().starts_with("range:"))
+                    .map(|l| l.splitn(2, ':').nth(1).unwrap().trim().to_string());
+
+                let (status, headers, body) = respond(&method, range.as_deref());
+                let mut out = format!("HTTP/1.1 {}\r\n", status);
+                for (k, v) in &headers {
+                    out.push_str(&format!("{}: {}\r\n", k, v));
+                }
+                out.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+                let _ = socket.write_all(out.as_bytes()).await;
+                let _ = socket.write_all(&body).await;
+                let _ = socket.shutdown().await;
+            }
+        });
+
+        (format!("http://{}", addr), requests)
+    }
+
+    #[rocket::tokio::test]
+    async fn sequential_reads_coalesce_onto_a_single_request() {
+        let content = b"abcdefghijklmnopqrstuvwxyz".to_vec();
+        let full_len = content.len();
+        let body_for_range = content.clone();
+
+        let (url, requests) = spawn_mock(move |method, range| match method {
+            "HEAD" => (
+                "200 OK",
+                vec![
+                    ("Accept-Ranges", "bytes".into()),
+                    ("Content-Length", full_len.to_string()),
+                ],
+                Vec::new(),
+            ),
+            _ => {
+                let start: usize = range
+                    .and_then(|r| r.strip_prefix("bytes="))
+                    .and_then(|r| r.strip_suffix('-'))
+                    .and_then(|r| r.parse().ok())
+                    .unwrap_or(0);
+                (
+                    "206 Partial Content",
+                    vec![(
+                        "Content-Range",
+                        format!("bytes {}-{}/{}", start, full_len - 1, full_len),
+                    )],
+                    body_for_range[start..].to_vec(),
+                )
+            }
+        })
+        .await;
+
+        let mut reader = HttpRangeReader::new(url).await.unwrap();
+        assert!(reader.accepts_ranges());
+
+        let mut collected = Vec::new();
+        let mut chunk = [0u8; 5];
+        loop {
+            let n = reader.read(&mut chunk).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            collected.extend_from_slice(&chunk[..n]);
+        }
+
+        assert_eq!(collected, content);
+        // One HEAD probe plus exactly one GET, no matter how many small reads it took.
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[rocket::tokio::test]
+    async fn a_200_response_to_a_ranged_read_is_rejected() {
+        let content = b"abcdefghijklmnopqrstuvwxyz".to_vec();
+        let full_len = content.len();
+        let misbehaving_body = content.clone();
+
+        let (url, _requests) = spawn_mock(move |method, _range| match method {
+            "HEAD" => (
+                "200 OK",
+                vec![
+                    ("Accept-Ranges", "bytes".into()),
+                    ("Content-Length", full_len.to_string()),
+                ],
+                Vec::new(),
+            ),
+            // Ignores `Range` and answers with the whole body and a plain 200, as a
+            // misconfigured origin might.
+            _ => ("200 OK", vec![], misbehaving_body.clone()),
+        })
+        .await;
+
+        let mut reader = HttpRangeReader::new(url).await.unwrap();
+        reader.seek(io::SeekFrom::Start(5)).await.unwrap();
+
+        let mut chunk = [0u8; 5];
+        let err = reader.read(&mut chunk).await.unwrap_err();
+        assert!(err.to_string().contains("expected 206 Partial Content"));
+    }
+
+    #[rocket::tokio::test]
+    async fn probing_a_206_without_a_content_range_is_an_error() {
+        // No HEAD support, so `new` falls back to the `bytes=0-0` probe; that probe answers
+        // 206 (as if ranges are supported) but omits `Content-Range`, so its `Content-Length`
+        // of `1` must not be mistaken for the resource's total size.
+        let (url, _requests) = spawn_mock(|method, _range| match method {
+            "HEAD" => ("404 Not Found", vec![], Vec::new()),
+            _ => ("206 Partial Content", vec![], vec![b'a']),
+        })
+        .await;
+
+        let err = HttpRangeReader::new(url).await.unwrap_err();
+        assert!(err.to_string().contains("Content-Range"));
+    }
+}
@@ -8,8 +8,8 @@
 //! use rocket_seek_stream::SeekStream;
 //!
 //! #[get("/")]
-//! fn home<'a>() -> std::io::Result<SeekStream<'a>> {
-//!     SeekStream::from_path("kosmodrom.webm")
+//! async fn home() -> std::io::Result<SeekStream> {
+//!     SeekStream::from_path("kosmodrom.webm").await
 //! }
 //!
 //! #[launch]
@@ -20,7 +20,9 @@
 //!
 //! ```
 
+mod http_range_reader;
 mod multipart;
 mod seekstream;
 
+pub use http_range_reader::HttpRangeReader;
 pub use seekstream::{ReadSeek, SeekStream};
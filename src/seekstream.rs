@@ -1,25 +1,107 @@
 #![allow(unused_must_use)]
 
 use crate::multipart::MultipartReader;
-use futures::executor::block_on;
-use rocket::futures;
+use httpdate::{fmt_http_date, parse_http_date};
 use rocket::response::{self, Responder, Response};
 use rocket::tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
-use rocket::tokio::runtime::Handle;
-use std::cell::RefCell;
+use std::io::{Cursor, SeekFrom};
 use std::path::Path;
 use std::pin::Pin;
-use tree_magic;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// A caller-supplied mime sniffer: given the sampled prelude, return a content type, or
+/// `None` to fall back to the built-in detector.
+pub type MimeDetector = Arc<dyn Fn(&[u8]) -> Option<String> + Send + Sync>;
+
+/// The default number of bytes sampled from the beginning of a stream when sniffing its
+/// mime type.
+const DEFAULT_SNIFF_SAMPLE_SIZE: usize = 256;
 
 /// Alias trait for AsyncRead + AsyncSeek + Send
 pub trait ReadSeek: AsyncRead + AsyncSeek + Send {}
 impl<T: AsyncRead + AsyncSeek + Send> ReadSeek for T {}
 
-/// Infer the mime type of a stream of bytes using an excerpt from the beginning of the stream
-fn infer_mime_type(prelude: &[u8]) -> String {
-    return tree_magic::from_u8(prelude);
+/// The crate's built-in mime detector, selected at compile time via the `infer` cargo
+/// feature. Defaults to the C-library-backed `tree_magic`; the `infer` feature switches to
+/// the pure-Rust `infer` crate instead.
+#[cfg(not(feature = "infer"))]
+fn builtin_detect(prelude: &[u8]) -> Option<String> {
+    let mime = tree_magic::from_u8(prelude);
+    if mime == "application/octet-stream" {
+        None
+    } else {
+        Some(mime)
+    }
+}
+
+#[cfg(feature = "infer")]
+fn builtin_detect(prelude: &[u8]) -> Option<String> {
+    infer::get(prelude).map(|kind| kind.mime_type().to_string())
 }
 
+/// Quote a caller-supplied validator value into a well-formed `ETag` field value,
+/// preserving a leading `W/` weak-validator marker if one was given.
+fn quote_etag(raw: &str) -> String {
+    let (weak, raw) = match raw.strip_prefix("W/") {
+        Some(rest) => (true, rest),
+        None => (false, raw),
+    };
+    let opaque = raw.trim_matches('"');
+    if weak {
+        format!("W/\"{}\"", opaque)
+    } else {
+        format!("\"{}\"", opaque)
+    }
+}
+
+/// Strip the `W/` weak-validator marker and surrounding quotes from an `ETag` value.
+fn etag_opaque(tag: &str) -> &str {
+    tag.trim().trim_start_matches("W/").trim_matches('"')
+}
+
+/// RFC 7232 weak comparison: the opaque tags match, ignoring weak markers.
+fn etag_weak_eq(a: &str, b: &str) -> bool {
+    etag_opaque(a) == etag_opaque(b)
+}
+
+/// RFC 7232 strong comparison: neither side is weak, and the tags match exactly.
+fn etag_strong_eq(a: &str, b: &str) -> bool {
+    let a = a.trim();
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+/// Evaluate a comma-separated `If-Match`/`If-None-Match` field against the current validator.
+fn etag_list_matches(header: &str, current: Option<&str>, strong: bool) -> bool {
+    let current = match current {
+        Some(c) => c,
+        None => return false,
+    };
+    header.split(',').map(|t| t.trim()).any(|tag| {
+        tag == "*" || if strong { etag_strong_eq(tag, current) } else { etag_weak_eq(tag, current) }
+    })
+}
+
+/// Evaluate an `If-Range` field against the current validators. `If-Range` always uses a
+/// strong comparison: a weak `ETag`, or a `Last-Modified` that doesn't match to the second,
+/// means the range request should be ignored in favor of the full entity.
+///
+/// Note that [`from_path`](SeekStream::from_path) only ever derives a *weak* `ETag` (it's
+/// built from the file's length and modification time, not its bytes, so it can't back a
+/// strong validator), which per RFC 7232 §3.3 can never satisfy an `If-Range` comparison. A
+/// client resuming a download against a `from_path`-served stream should send `If-Range`
+/// against the `Last-Modified` date instead; that's preserved for exactly this reason.
+fn if_range_satisfied(if_range: &str, etag: Option<&str>, last_modified: Option<SystemTime>) -> bool {
+    if let Ok(since) = parse_http_date(if_range) {
+        return last_modified.map_or(false, |m| fmt_http_date(m) == fmt_http_date(since));
+    }
+    etag.map_or(false, |current| etag_strong_eq(if_range, current))
+}
+
+/// The default cap on the number of distinct parts a multi-range request may produce before
+/// they're coalesced down to a single covering span.
+const DEFAULT_MAX_RANGES: usize = 16;
+
 /// Serves a readable and seekable stream,
 /// The mime type can optionally be inferred by taking a sample of
 /// bytes from the beginning of the stream.
@@ -27,14 +109,27 @@ fn infer_mime_type(prelude: &[u8]) -> String {
 /// The Accept Ranges header will always be set.
 /// If a range request is received, it will respond with the requested offset.
 /// Multipart range requests are also supported.
-pub struct SeekStream<'a> {
-    stream: RefCell<Pin<Box<dyn ReadSeek>>>,
+///
+/// `Responder::respond_to` is synchronous, so a `SeekStream` must arrive there with its
+/// length and content type already known. Construct one with both supplied (e.g.
+/// `with_opts(stream, len, "video/webm")`) and it's ready to return as-is; if either is
+/// omitted, await [`prepare`](Self::prepare) before returning it from the route handler.
+/// [`from_path`](Self::from_path) does this internally.
+pub struct SeekStream {
+    stream: Pin<Box<dyn ReadSeek>>,
     length: Option<u64>,
-    content_type: Option<&'a str>,
+    content_type: Option<String>,
+    etag: Option<String>,
+    last_modified: Option<SystemTime>,
+    max_ranges: usize,
+    merge_adjacent_ranges: bool,
+    sniff_sample_size: usize,
+    detector: Option<MimeDetector>,
+    prelude: Vec<u8>,
 }
 
-impl<'a> SeekStream<'a> {
-    pub fn new<T>(s: T) -> SeekStream<'a>
+impl SeekStream {
+    pub fn new<T>(s: T) -> SeekStream
     where
         T: AsyncRead + AsyncSeek + Send + 'static,
     {
@@ -44,30 +139,132 @@ impl<'a> SeekStream<'a> {
     pub fn with_opts<T>(
         stream: T,
         stream_len: impl Into<Option<u64>>,
-        content_type: impl Into<Option<&'a str>>,
-    ) -> SeekStream<'a>
+        content_type: impl Into<Option<&str>>,
+    ) -> SeekStream
     where
         T: AsyncRead + AsyncSeek + Send + 'static,
     {
         SeekStream {
-            stream: RefCell::new(Box::pin(stream)),
+            stream: Box::pin(stream),
             length: stream_len.into(),
-            content_type: content_type.into(),
+            content_type: content_type.into().map(String::from),
+            etag: None,
+            last_modified: None,
+            max_ranges: DEFAULT_MAX_RANGES,
+            merge_adjacent_ranges: true,
+            sniff_sample_size: DEFAULT_SNIFF_SAMPLE_SIZE,
+            detector: None,
+            prelude: Vec::new(),
         }
     }
 
+    /// Set a validator `ETag` for this stream, used to answer conditional and `If-Range`
+    /// requests. Pass a value prefixed with `W/` to mark it as a weak validator; quotes are
+    /// added automatically if not already present.
+    pub fn etag(mut self, etag: impl AsRef<str>) -> Self {
+        self.etag = Some(quote_etag(etag.as_ref()));
+        self
+    }
+
+    /// Set the `Last-Modified` validator for this stream, used to answer conditional and
+    /// `If-Range` requests.
+    pub fn last_modified(mut self, modified: SystemTime) -> Self {
+        self.last_modified = Some(modified);
+        self
+    }
+
+    /// Cap the number of distinct parts a multi-range request can produce. Once a request's
+    /// ranges (after merging) exceed this count, they're coalesced into a single span
+    /// covering all of them rather than served as an oversized `multipart/byteranges`
+    /// response. Defaults to 16.
+    pub fn max_ranges(mut self, max_ranges: usize) -> Self {
+        self.max_ranges = max_ranges;
+        self
+    }
+
+    /// Toggle whether adjacent or overlapping requested ranges are merged into a single part
+    /// before building a multipart response. Defaults to `true`.
+    pub fn merge_adjacent_ranges(mut self, merge: bool) -> Self {
+        self.merge_adjacent_ranges = merge;
+        self
+    }
+
+    /// Override the number of bytes sampled from the beginning of the stream when sniffing
+    /// its mime type. Ignored if a content type was already supplied. Defaults to 256.
+    pub fn sniff_sample_size(mut self, sample_size: usize) -> Self {
+        self.sniff_sample_size = sample_size;
+        self
+    }
+
+    /// Supply a custom closure to consult before the built-in detector when sniffing a mime
+    /// type from the stream's prelude. Returning `None` falls back to the built-in detector.
+    pub fn sniff_with<F>(mut self, detector: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<String> + Send + Sync + 'static,
+    {
+        self.detector = Some(Arc::new(detector));
+        self
+    }
+
     /// Serve content from a file path. The mime type will be inferred by taking a sample from
-    /// The beginning of the stream.
-    pub fn from_path<T: AsRef<Path>>(p: T) -> std::io::Result<Self> {
-        let handle = Handle::current();
-        handle.enter();
-        let file = match block_on(rocket::tokio::fs::File::open(p.as_ref())) {
-            Ok(f) => f,
-            Err(e) => return Err(e),
-        };
-        let len = block_on(file.metadata()).unwrap().len();
+    /// The beginning of the stream. `Last-Modified` is populated from the file's metadata, and
+    /// a weak `ETag` is derived from its length and modification time.
+    ///
+    /// Because that `ETag` is weak, it can never satisfy an `If-Range` comparison (RFC 7232
+    /// requires a strong validator there); clients resuming a download against a stream
+    /// served this way should send `If-Range` against `Last-Modified`, not the `ETag`.
+    ///
+    /// This opens the file and reads its metadata asynchronously, so it must be awaited from
+    /// an async route handler rather than called from a blocking context.
+    pub async fn from_path<T: AsRef<Path>>(p: T) -> std::io::Result<Self> {
+        let file = rocket::tokio::fs::File::open(p.as_ref()).await?;
+        let metadata = file.metadata().await?;
+        let len = metadata.len();
+
+        let mut stream = Self::with_opts(file, len, None);
+        if let Ok(modified) = metadata.modified() {
+            let mtime = modified
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            stream.etag = Some(format!("W/\"{:x}-{:x}\"", len, mtime));
+            stream.last_modified = Some(modified);
+        }
 
-        Ok(Self::with_opts(file, len, None))
+        stream.prepare().await
+    }
+
+    /// Resolve everything about this stream that requires `.await`ing it: its length, if not
+    /// already known, and a sniffed mime type (by sampling its first bytes), if no content
+    /// type was supplied. `Responder::respond_to` is a synchronous trait method and so cannot
+    /// do this itself; this is the async step a route handler awaits instead, mirroring what
+    /// [`from_path`](Self::from_path) already does internally. A `SeekStream` that still has
+    /// either unresolved when it reaches `respond_to` answers with a `500`.
+    pub async fn prepare(mut self) -> std::io::Result<Self> {
+        if self.length.is_none() {
+            let old_pos = self.stream.seek(SeekFrom::Current(0)).await?;
+            let len = self.stream.seek(SeekFrom::End(0)).await?;
+            self.stream.seek(SeekFrom::Start(old_pos)).await?;
+            self.length = Some(len);
+        }
+
+        if self.content_type.is_none() {
+            let mut prelude = vec![0u8; self.sniff_sample_size];
+            let c = self.stream.read(&mut prelude).await?;
+            prelude.truncate(c);
+
+            let mime_type = self
+                .detector
+                .as_ref()
+                .and_then(|detect| detect(&prelude))
+                .or_else(|| builtin_detect(&prelude))
+                .unwrap_or_else(|| "application/octet-stream".to_string());
+
+            self.prelude = prelude;
+            self.content_type = Some(mime_type);
+        }
+
+        Ok(self)
     }
 }
 
@@ -77,18 +274,28 @@ fn to_satisfiable_range(
     to: Option<u64>,
     length: u64,
 ) -> Result<(u64, u64), &'static str> {
+    if length == 0 {
+        return Err("A byte-range-spec cannot be satisfied by a zero-length resource.");
+    }
+
     let (start, mut end) = match (from, to) {
-        (Some(x), Some(z)) => (x, z),                // FromToAll
-        (Some(x), None) => (x, length - 1),          // FromTo
-        (None, Some(z)) => (length - z, length - 1), // FromEnd
+        (Some(x), Some(z)) => (x, z),                             // FromToAll
+        (Some(x), None) => (x, length - 1),                       // FromTo
+        (None, Some(z)) => (length.saturating_sub(z), length - 1), // FromEnd
         (None, None) => return Err("You need at least one value to satisfy a range request"),
     };
 
+    if start >= length {
+        return Err("A byte-range-spec is invalid if the first-byte-pos is past the end of the resource.");
+    }
     if end < start {
         return Err("A byte-range-spec is invalid if the last-byte-pos value is present and less than the first-byte-pos.");
     }
-    if end > length {
-        end = length
+    if end >= length {
+        end = length - 1;
+    }
+    if end < start {
+        return Err("A byte-range-spec is invalid if the last-byte-pos value is present and less than the first-byte-pos.");
     }
 
     Ok((start, end))
@@ -103,65 +310,300 @@ fn range_header_parts(header: &range_header::ByteRange) -> (Option<u64>, Option<
     }
 }
 
-#[rocket::async_trait]
-impl<'r> Responder<'r, 'static> for SeekStream<'r> {
-    fn respond_to(self, req: &'r rocket::Request) -> response::Result<'static> {
-        use rocket::http::Status;
-        use std::io::SeekFrom;
-        let handle = Handle::current();
-        handle.enter();
+/// Sort the requested ranges by start offset and, if `merge_adjacent` is set, fold any range
+/// whose start falls within or immediately after the previous one into that previous range.
+/// This collapses the overlapping/adjacent one-byte ranges a client could otherwise use to
+/// force an oversized `multipart/byteranges` response.
+fn coalesce_ranges(mut ranges: Vec<(u64, u64)>, merge_adjacent: bool) -> Vec<(u64, u64)> {
+    ranges.sort();
 
-        const SERVER_ERROR: Status = Status::InternalServerError;
-        const RANGE_ERROR: Status = Status::RangeNotSatisfiable;
+    if !merge_adjacent {
+        ranges.dedup();
+        return ranges;
+    }
 
-        // Get the total length of the stream if not already specified
-        let stream_len = match self.length {
-            Some(x) => x,
-            _ => {
-                let mut borrowed = self.stream.borrow_mut();
-                let old_pos = match block_on(borrowed.seek(SeekFrom::Current(0))) {
-                    Ok(x) => x,
-                    Err(_) => return Err(SERVER_ERROR),
-                };
-                let len = match block_on(borrowed.seek(SeekFrom::End(0))) {
-                    Ok(x) => x,
-                    Err(_) => return Err(SERVER_ERROR),
-                };
-                match block_on(borrowed.seek(SeekFrom::Start(old_pos))) {
-                    Ok(_) => len,
-                    Err(_) => return Err(SERVER_ERROR),
-                }
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(&mut (_, ref mut prev_end)) if start <= *prev_end + 1 => {
+                *prev_end = std::cmp::max(*prev_end, end);
             }
-        };
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
 
-        // Get the mime type, either by inferring it from the stream
-        // Or the optional value set in the struct
-        let mime_type = match self.content_type {
-            Some(x) => String::from(x),
-            None => {
-                // Infer the mime type of the stream by taking at most a 256 byte sample from the beginning
-                // And passing it to the infer_mime_type function
-                let mut prelude: [u8; 256] = [0; 256];
+/// If more than `max_ranges` distinct parts remain after coalescing, give up on serving them
+/// as separate parts and collapse them into a single span covering all of them.
+fn enforce_range_cap(ranges: Vec<(u64, u64)>, max_ranges: usize) -> Vec<(u64, u64)> {
+    if ranges.len() <= max_ranges {
+        return ranges;
+    }
 
-                let c = block_on(self.stream.borrow_mut().read(&mut prelude))
-                    .map_err(|_| SERVER_ERROR)?;
+    let start = ranges.iter().map(|&(s, _)| s).min().unwrap();
+    let end = ranges.iter().map(|&(_, e)| e).max().unwrap();
+    vec![(start, end)]
+}
 
-                // Seek to the beginning of the stream to reset the data we took for the sample
-                block_on(self.stream.borrow_mut().seek(std::io::SeekFrom::Start(0)))
-                    .map_err(|_| SERVER_ERROR)?;
+#[cfg(test)]
+mod range_math_tests {
+    use super::*;
 
-                infer_mime_type(&prelude[..c])
+    #[test]
+    fn from_to_all_is_used_verbatim() {
+        assert_eq!(to_satisfiable_range(Some(2), Some(5), 10), Ok((2, 5)));
+    }
+
+    #[test]
+    fn from_to_runs_to_the_end() {
+        assert_eq!(to_satisfiable_range(Some(2), None, 10), Ok((2, 9)));
+    }
+
+    #[test]
+    fn from_end_counts_back_from_the_length() {
+        assert_eq!(to_satisfiable_range(None, Some(3), 10), Ok((7, 9)));
+    }
+
+    #[test]
+    fn from_end_larger_than_the_length_clamps_to_the_start() {
+        // A suffix-range request for more bytes than the resource has used to underflow
+        // `length - z`; it should clamp to byte 0 instead.
+        assert_eq!(to_satisfiable_range(None, Some(100), 10), Ok((0, 9)));
+    }
+
+    #[test]
+    fn neither_bound_is_an_error() {
+        assert!(to_satisfiable_range(None, None, 10).is_err());
+    }
+
+    #[test]
+    fn a_zero_length_resource_is_never_satisfiable() {
+        // `length - 1` in the FromTo/FromEnd arms used to run before this guard existed,
+        // panicking on the `u64` underflow for a `Range` request against an empty resource.
+        assert!(to_satisfiable_range(Some(0), None, 0).is_err());
+        assert!(to_satisfiable_range(None, Some(1), 0).is_err());
+        assert!(to_satisfiable_range(Some(0), Some(0), 0).is_err());
+    }
+
+    #[test]
+    fn start_past_the_end_of_the_resource_is_an_error() {
+        assert!(to_satisfiable_range(Some(10), None, 10).is_err());
+        assert!(to_satisfiable_range(Some(20), Some(25), 10).is_err());
+    }
+
+    #[test]
+    fn end_before_start_is_an_error() {
+        assert!(to_satisfiable_range(Some(5), Some(2), 10).is_err());
+    }
+
+    #[test]
+    fn end_past_the_last_byte_clamps_to_it() {
+        assert_eq!(to_satisfiable_range(Some(2), Some(100), 10), Ok((2, 9)));
+    }
+
+    #[test]
+    fn coalesce_sorts_and_merges_overlapping_and_adjacent_ranges() {
+        let ranges = vec![(10, 19), (0, 5), (6, 9), (25, 30)];
+        assert_eq!(coalesce_ranges(ranges, true), vec![(0, 19), (25, 30)]);
+    }
+
+    #[test]
+    fn coalesce_without_merging_only_sorts_and_dedups() {
+        let ranges = vec![(10, 19), (0, 5), (0, 5)];
+        assert_eq!(coalesce_ranges(ranges, false), vec![(0, 5), (10, 19)]);
+    }
+
+    #[test]
+    fn enforce_range_cap_leaves_ranges_under_the_cap_alone() {
+        let ranges = vec![(0, 1), (2, 3)];
+        assert_eq!(enforce_range_cap(ranges.clone(), 2), ranges);
+    }
+
+    #[test]
+    fn enforce_range_cap_collapses_ranges_over_the_cap_into_one_span() {
+        let ranges = vec![(0, 1), (10, 11), (20, 21)];
+        assert_eq!(enforce_range_cap(ranges, 2), vec![(0, 21)]);
+    }
+}
+
+#[cfg(test)]
+mod etag_tests {
+    use super::*;
+
+    #[test]
+    fn quote_etag_adds_quotes_and_preserves_the_weak_marker() {
+        assert_eq!(quote_etag("abc"), "\"abc\"");
+        assert_eq!(quote_etag("\"abc\""), "\"abc\"");
+        assert_eq!(quote_etag("W/abc"), "W/\"abc\"");
+        assert_eq!(quote_etag("W/\"abc\""), "W/\"abc\"");
+    }
+
+    #[test]
+    fn weak_comparison_ignores_the_weak_marker() {
+        assert!(etag_weak_eq("\"abc\"", "W/\"abc\""));
+        assert!(!etag_weak_eq("\"abc\"", "\"def\""));
+    }
+
+    #[test]
+    fn strong_comparison_rejects_weak_validators() {
+        assert!(etag_strong_eq("\"abc\"", "\"abc\""));
+        assert!(!etag_strong_eq("W/\"abc\"", "\"abc\""));
+    }
+
+    #[test]
+    fn etag_list_matches_handles_the_wildcard_and_missing_current() {
+        assert!(etag_list_matches("*", Some("\"abc\""), true));
+        assert!(!etag_list_matches("*", None, true));
+        assert!(etag_list_matches("\"xyz\", \"abc\"", Some("\"abc\""), true));
+        assert!(!etag_list_matches("\"xyz\"", Some("\"abc\""), true));
+    }
+
+    #[test]
+    fn if_range_requires_a_strong_etag_match() {
+        assert!(if_range_satisfied("\"abc\"", Some("\"abc\""), None));
+        assert!(!if_range_satisfied("W/\"abc\"", Some("W/\"abc\""), None));
+    }
+
+    #[test]
+    fn if_range_falls_back_to_an_http_date_against_last_modified() {
+        let modified = SystemTime::now();
+        let date = fmt_http_date(modified);
+        assert!(if_range_satisfied(&date, None, Some(modified)));
+    }
+}
+
+/// How many bytes are read from the underlying stream at a time while streaming a single
+/// range's payload; mirrors `multipart::CHUNK_SIZE`.
+const RANGE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Build a body that seeks to `start` and streams `end - start + 1` bytes. The seek happens
+/// the first time the returned body is polled rather than up front, so this can be called
+/// from the synchronous `respond_to` the same way [`MultipartReader::new`] already seeks
+/// lazily for each of its parts.
+fn single_range_body<T: ReadSeek + 'static>(
+    stream: T,
+    start: u64,
+    end: u64,
+) -> Pin<Box<dyn AsyncRead + Send>> {
+    let body: Pin<Box<dyn rocket::futures::Stream<Item = std::io::Result<bytes::Bytes>> + Send>> =
+        Box::pin(async_stream::stream! {
+            let mut stream = Box::pin(stream);
+            if let Err(e) = stream.as_mut().seek(SeekFrom::Start(start)).await {
+                yield Err(e);
+                return;
             }
-        };
 
-        // Set the response headers
+            let mut remaining = end + 1 - start;
+            let mut buf = vec![0u8; RANGE_CHUNK_SIZE];
+            while remaining > 0 {
+                let want = std::cmp::min(buf.len() as u64, remaining) as usize;
+                match stream.as_mut().read(&mut buf[..want]).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        remaining -= n as u64;
+                        yield Ok(bytes::Bytes::copy_from_slice(&buf[..n]));
+                    }
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                }
+            }
+        });
+
+    Box::pin(tokio_util::io::StreamReader::new(body))
+}
+
+impl<'r> Responder<'r, 'static> for SeekStream {
+    fn respond_to(mut self, req: &'r rocket::Request<'_>) -> response::Result<'static> {
+        use rocket::http::Status;
+
+        const SERVER_ERROR: Status = Status::InternalServerError;
+        const RANGE_ERROR: Status = Status::RangeNotSatisfiable;
+
+        // Evaluate RFC 7232 preconditions up front, before touching the stream at all, so a
+        // mismatched validator never pays for a seek or a mime sniff. ETag takes precedence
+        // over date-based validators per spec.
         let mut resp = Response::new();
         resp.set_raw_header("Accept-Ranges", "bytes");
+        if let Some(etag) = &self.etag {
+            resp.set_raw_header("ETag", etag.clone());
+        }
+        if let Some(modified) = self.last_modified {
+            resp.set_raw_header("Last-Modified", fmt_http_date(modified));
+        }
+
+        // RFC 7232 §6 evaluates If-Match/If-Unmodified-Since (-> 412) before
+        // If-None-Match/If-Modified-Since (-> 304), so a request that fails the former takes
+        // precedence over one that would otherwise be satisfied by the latter.
+        let headers = req.headers();
+        if let Some(if_match) = headers.get_one("If-Match") {
+            if !etag_list_matches(if_match, self.etag.as_deref(), true) {
+                resp.set_status(Status::PreconditionFailed);
+                return Ok(resp);
+            }
+        } else if let Some(if_unmodified_since) = headers.get_one("If-Unmodified-Since") {
+            if let (Some(modified), Ok(since)) =
+                (self.last_modified, parse_http_date(if_unmodified_since))
+            {
+                if modified > since && fmt_http_date(modified) != fmt_http_date(since) {
+                    resp.set_status(Status::PreconditionFailed);
+                    return Ok(resp);
+                }
+            }
+        }
+
+        if let Some(if_none_match) = headers.get_one("If-None-Match") {
+            if etag_list_matches(if_none_match, self.etag.as_deref(), false) {
+                resp.set_status(Status::NotModified);
+                return Ok(resp);
+            }
+        } else if let Some(if_modified_since) = headers.get_one("If-Modified-Since") {
+            if let (Some(modified), Ok(since)) =
+                (self.last_modified, parse_http_date(if_modified_since))
+            {
+                if fmt_http_date(modified) == fmt_http_date(since) || modified <= since {
+                    resp.set_status(Status::NotModified);
+                    return Ok(resp);
+                }
+            }
+        }
+
+        // The length and content type must already be resolved: `respond_to` is synchronous
+        // and can't await the stream itself to discover them. A `SeekStream` returned from a
+        // route handler without calling `.prepare()` first has no way to recover here.
+        let stream_len = match self.length {
+            Some(x) => x,
+            None => return Err(SERVER_ERROR),
+        };
+        let mime_type = match self.content_type.clone() {
+            Some(x) => x,
+            None => return Err(SERVER_ERROR),
+        };
+        // Any bytes sampled by `prepare` for mime sniffing are kept around rather than
+        // seeking the stream back to zero, since a seek can be expensive for some sources;
+        // they're logically prepended to the body instead.
+        let prelude_bytes = std::mem::take(&mut self.prelude);
+
         resp.set_raw_header("Content-Type", mime_type.clone());
 
         // If the range header exists, set the response status code to
-        // 206 partial content and seek the stream to the requested position
-        if let Some(x) = req.headers().get_one("Range") {
+        // 206 partial content and seek the stream to the requested position, unless an
+        // `If-Range` validator is present and no longer matches the current resource.
+        if let Some(x) = headers.get_one("Range") {
+            if let Some(if_range) = headers.get_one("If-Range") {
+                if !if_range_satisfied(if_range, self.etag.as_deref(), self.last_modified) {
+                    resp.set_raw_header("Content-Length", format!("{}", stream_len));
+                    if prelude_bytes.is_empty() {
+                        resp.set_streamed_body(self.stream);
+                    } else {
+                        resp.set_streamed_body(Cursor::new(prelude_bytes).chain(self.stream));
+                    }
+                    return Ok(resp);
+                }
+            }
+
             let (ranges, errors) = range_header::ByteRange::parse(x)
                 .iter()
                 .map(|x| range_header_parts(&x))
@@ -172,27 +614,20 @@ impl<'r> Responder<'r, 'static> for SeekStream<'r> {
             // Or the list of ranges is empty.
             // Return a range error.
             if errors.len() > 0 || ranges.len() == 0 {
-                for e in errors {
-                    println!("{:?}", e.unwrap_err());
-                }
                 return Err(RANGE_ERROR);
             }
 
             // Unwrap all the results
-            let mut ranges: Vec<(u64, u64)> = ranges.iter().map(|x| x.unwrap()).collect();
+            let ranges: Vec<(u64, u64)> = ranges.iter().map(|x| x.unwrap()).collect();
 
-            // de-duplicate the list of ranges
-            ranges.sort();
-            ranges.dedup_by(|&mut (a, b), &mut (c, d)| a == c && b == d);
+            // Merge overlapping/adjacent ranges, then bound how many distinct parts a single
+            // request can force us to serve.
+            let ranges = coalesce_ranges(ranges, self.merge_adjacent_ranges);
+            let ranges = enforce_range_cap(ranges, self.max_ranges);
 
             // Stream multipart/bytes if multiple ranges have been requested
             if ranges.len() > 1 {
-                let rd = MultipartReader::new(
-                    self.stream.into_inner(),
-                    stream_len,
-                    mime_type.clone(),
-                    ranges,
-                );
+                let rd = MultipartReader::new(self.stream, stream_len, mime_type.clone(), ranges);
 
                 resp.set_raw_header(
                     "Content-Type",
@@ -203,19 +638,6 @@ impl<'r> Responder<'r, 'static> for SeekStream<'r> {
                 // Stream a single range request if only one was present in the byte ranges
                 let &(start, end) = ranges.get(0).unwrap();
 
-                // Seek the stream to the desired position
-                match block_on(self.stream.borrow_mut().seek(SeekFrom::Start(start)))
-                    .map_err(|_| SERVER_ERROR)
-                {
-                    Ok(_) => (),
-                    Err(_) => return Err(SERVER_ERROR),
-                };
-
-                let mut stream: Pin<Box<dyn AsyncRead + Send>> = Box::pin(self.stream.into_inner());
-                if end + 1 < stream_len {
-                    stream = Box::pin(stream.take(end + 1 - start));
-                }
-
                 resp.set_raw_header(
                     "Content-Range",
                     format!("bytes {}-{}/{}", start, end, stream_len),
@@ -225,12 +647,16 @@ impl<'r> Responder<'r, 'static> for SeekStream<'r> {
                 resp.set_raw_header("Content-Length", format!("{}", end + 1 - start));
                 resp.set_status(rocket::http::Status::PartialContent);
 
-                resp.set_streamed_body(stream)
+                resp.set_streamed_body(single_range_body(self.stream, start, end));
             }
         } else {
             // No range request; Response with the entire stream
             resp.set_raw_header("Content-Length", format!("{}", stream_len));
-            resp.set_streamed_body(self.stream.into_inner());
+            if prelude_bytes.is_empty() {
+                resp.set_streamed_body(self.stream);
+            } else {
+                resp.set_streamed_body(Cursor::new(prelude_bytes).chain(self.stream));
+            }
         }
 
         Ok(resp)